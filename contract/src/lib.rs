@@ -13,33 +13,102 @@
 
 // To conserve gas, efficient serialization is achieved through Borsh (http://borsh.io/)
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
 use near_sdk::wee_alloc;
-use near_sdk::{env, near_bindgen, Balance, Promise};
+use near_sdk::{
+    env, ext_contract, near_bindgen, AccountId, Balance, Gas, Promise, PromiseResult,
+};
 use std::collections::HashMap;
 
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+/// Gas reserved for `resolve_withdraw`/`resolve_reclaim`/`resolve_withdraw_ft`, the callbacks
+/// that inspect whether the outgoing transfer Promise actually succeeded.
+const GAS_FOR_RESOLVE_TRANSFER: Gas = 5_000_000_000_000;
+
+/// Gas attached to the `ft_transfer` call made from `withdraw_ft`.
+const GAS_FOR_FT_TRANSFER: Gas = 10_000_000_000_000;
+
+/// Guards state-changing methods that move value against accidental or
+/// relayed calls, mirroring the 1-yoctoNEAR convention used across NEP-141
+/// token contracts: only a full-access key can attach an exact yoctoNEAR.
+pub fn assert_one_yocto() {
+    assert_eq!(
+        env::attached_deposit(),
+        1,
+        "Requires attached deposit of exactly 1 yoctoNEAR"
+    );
+}
+
+#[ext_contract(ext_self)]
+pub trait ExtSelf {
+    fn resolve_withdraw(&mut self, hash: String, amount: Balance);
+    fn resolve_reclaim(&mut self, hash: String, amount: Balance);
+    fn resolve_withdraw_ft(&mut self, hash: String, amount: Balance);
+}
+
+#[ext_contract(ext_fungible_token)]
+pub trait FungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+/// A single locked native NEAR deposit: who posted it, how much, and
+/// (optionally) when the depositor may reclaim it if nobody reveals the
+/// preimage.
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct Record {
+    amount: Balance,
+    depositor: AccountId,
+    unlock_timestamp: u64,
+}
+
+/// A single locked NEP-141 deposit, posted via `ft_on_transfer`.
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct TokenRecord {
+    token_contract: AccountId,
+    amount: Balance,
+}
+
 // Structs in Rust are similar to other languages, and may include impl keyword as shown below
 // Note: the names of the structs are not important when calling the smart contract, but the function names are
 #[near_bindgen]
 #[derive(Default, BorshDeserialize, BorshSerialize)]
 pub struct Welcome {
-    records: HashMap<String, Balance>,
+    records: HashMap<String, Record>,
+    token_records: HashMap<String, TokenRecord>,
 }
 
 #[near_bindgen]
 impl Welcome {
+    /// Locks `attached_deposit` behind a sha256 commitment until either the
+    /// preimage is revealed (`withdraw`) or `unlock_timestamp` (nanoseconds
+    /// since epoch) passes and the depositor reclaims it (`reclaim`). `hash`
+    /// is the hex-encoded sha256 digest of a secret that the depositor
+    /// shares with whoever should be able to withdraw.
     #[payable]
-    pub fn deposit(&mut self, hash: String) {
-        let deposit: Balance = env::attached_deposit();
-        self.records.insert(hash, deposit);
+    pub fn deposit(&mut self, hash: String, unlock_timestamp: u64) {
+        assert_eq!(
+            self.records.get(&hash).map_or(0, |record| record.amount),
+            0,
+            "Key already in use"
+        );
+        let amount: Balance = env::attached_deposit();
+        let depositor = env::predecessor_account_id();
+        self.records.insert(
+            hash,
+            Record {
+                amount,
+                depositor,
+                unlock_timestamp,
+            },
+        );
     }
 
     pub fn get_deposit(&self, hash: String) -> Balance {
         match self.records.get(&hash) {
-            Some(deposit) => {
-                *deposit
+            Some(record) => {
+                record.amount
             }
             None => {
                 0
@@ -47,21 +116,182 @@ impl Welcome {
         }
     }
 
-    pub fn withdraw(&mut self, hash: String) -> bool {
-        match self.records.get(&hash.clone()) {
-            Some(deposit) => {
-                assert!(deposit > &0, "Missing deposit");
-                let account_id = env::predecessor_account_id();
-                Promise::new(account_id).transfer(*deposit);
-                self.records.insert(hash, 0);
-                true
+    pub fn get_token_deposit(&self, hash: String) -> Balance {
+        match self.token_records.get(&hash) {
+            Some(record) => {
+                record.amount
             }
             None => {
-                env::log(format!("Wrong key").as_bytes());
-                false
+                0
             }
         }
     }
+
+    /// Opens the box for whoever can produce the preimage of a stored
+    /// commitment on a native NEAR deposit (see `withdraw_ft` for NEP-141
+    /// deposits). The digest of `secret`, not `secret` itself, is the map
+    /// key, so knowing the hash alone (as passed to `get_deposit`) is not
+    /// enough to withdraw. The balance is zeroed optimistically and restored
+    /// by `resolve_withdraw` if the transfer Promise ends up failing, so a
+    /// dropped transfer never silently burns the deposit. Requires a 1
+    /// yoctoNEAR attachment, so only a full-access key can authorize it.
+    #[payable]
+    pub fn withdraw(&mut self, secret: String) -> Promise {
+        assert_one_yocto();
+        let hash = hex::encode(env::sha256(secret.as_bytes()));
+        let record = self.records.get(&hash).expect("Wrong key");
+        assert!(record.amount > 0, "Missing deposit");
+        let amount = record.amount;
+        let account_id = env::predecessor_account_id();
+        self.records.get_mut(&hash).unwrap().amount = 0;
+        Promise::new(account_id).transfer(amount).then(
+            ext_self::resolve_withdraw(
+                hash,
+                amount,
+                &env::current_account_id(),
+                0,
+                GAS_FOR_RESOLVE_TRANSFER,
+            ),
+        )
+    }
+
+    /// Private callback invoked after the `withdraw` transfer Promise
+    /// settles; restores the balance if the transfer failed so the deposit
+    /// isn't lost.
+    #[private]
+    pub fn resolve_withdraw(&mut self, hash: String, amount: Balance) {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "resolve_withdraw expects exactly one promise result"
+        );
+        let transfer_succeeded = matches!(env::promise_result(0), PromiseResult::Successful(_));
+        self.apply_withdraw_result(transfer_succeeded, hash, amount);
+    }
+
+    fn apply_withdraw_result(&mut self, transfer_succeeded: bool, hash: String, amount: Balance) {
+        if !transfer_succeeded {
+            self.records.get_mut(&hash).unwrap().amount = amount;
+        }
+    }
+
+    /// Lets the original depositor take back an unrevealed deposit once its
+    /// `unlock_timestamp` has passed, so a sender isn't stuck forever if the
+    /// counterparty never reveals the secret. Requires a 1 yoctoNEAR
+    /// attachment, so only a full-access key can authorize it. Like
+    /// `withdraw`, the balance is zeroed optimistically and restored by
+    /// `resolve_reclaim` if the transfer Promise ends up failing.
+    #[payable]
+    pub fn reclaim(&mut self, hash: String) -> Promise {
+        assert_one_yocto();
+        let record = self.records.get(&hash).expect("Wrong key");
+        assert!(record.amount > 0, "Missing deposit");
+        assert_eq!(
+            env::predecessor_account_id(),
+            record.depositor,
+            "Only the depositor can reclaim this deposit"
+        );
+        assert!(
+            env::block_timestamp() >= record.unlock_timestamp,
+            "Deposit is still locked"
+        );
+        let amount = record.amount;
+        let depositor = record.depositor.clone();
+        self.records.get_mut(&hash).unwrap().amount = 0;
+        Promise::new(depositor).transfer(amount).then(
+            ext_self::resolve_reclaim(
+                hash,
+                amount,
+                &env::current_account_id(),
+                0,
+                GAS_FOR_RESOLVE_TRANSFER,
+            ),
+        )
+    }
+
+    /// Private callback invoked after the `reclaim` transfer Promise
+    /// settles; restores the balance if the transfer failed so the deposit
+    /// isn't lost.
+    #[private]
+    pub fn resolve_reclaim(&mut self, hash: String, amount: Balance) {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "resolve_reclaim expects exactly one promise result"
+        );
+        let transfer_succeeded = matches!(env::promise_result(0), PromiseResult::Successful(_));
+        self.apply_withdraw_result(transfer_succeeded, hash, amount);
+    }
+
+    /// NEP-141 receiver hook: accepts tokens deposited via `ft_transfer_call`
+    /// against the calling token contract, locking them behind the sha256
+    /// commitment carried in `msg`. Returns `U128(0)` to accept the full
+    /// transferred amount.
+    pub fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> U128 {
+        assert_eq!(
+            self.token_records.get(&msg).map_or(0, |record| record.amount),
+            0,
+            "Key already in use"
+        );
+        let token_contract = env::predecessor_account_id();
+        let _ = sender_id;
+        self.token_records.insert(
+            msg,
+            TokenRecord {
+                token_contract,
+                amount: amount.0,
+            },
+        );
+        U128(0)
+    }
+
+    /// Token-aware counterpart of `withdraw`: reveals the preimage of a
+    /// NEP-141 commitment and issues a cross-contract `ft_transfer` back to
+    /// the caller instead of a native `Promise::transfer`. The balance is
+    /// zeroed optimistically and restored by `resolve_withdraw_ft` if the
+    /// `ft_transfer` Promise fails. Requires a 1 yoctoNEAR attachment, so
+    /// only a full-access key can authorize it.
+    #[payable]
+    pub fn withdraw_ft(&mut self, secret: String) -> Promise {
+        assert_one_yocto();
+        let hash = hex::encode(env::sha256(secret.as_bytes()));
+        let record = self.token_records.get(&hash).expect("Wrong key");
+        assert!(record.amount > 0, "Missing deposit");
+        let amount = record.amount;
+        let token_contract = record.token_contract.clone();
+        let account_id = env::predecessor_account_id();
+        self.token_records.get_mut(&hash).unwrap().amount = 0;
+        ext_fungible_token::ft_transfer(
+            account_id,
+            U128(amount),
+            None,
+            &token_contract,
+            1,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::resolve_withdraw_ft(
+            hash,
+            amount,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+    }
+
+    /// Private callback invoked after the `withdraw_ft` `ft_transfer`
+    /// Promise settles; restores the balance if the transfer failed.
+    #[private]
+    pub fn resolve_withdraw_ft(&mut self, hash: String, amount: Balance) {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "resolve_withdraw_ft expects exactly one promise result"
+        );
+        let transfer_succeeded = matches!(env::promise_result(0), PromiseResult::Successful(_));
+        if !transfer_succeeded {
+            self.token_records.get_mut(&hash).unwrap().amount = amount;
+        }
+    }
 }
 
 /*
@@ -91,7 +321,7 @@ mod tests {
             input,
             block_index: 0,
             block_timestamp: 0,
-            account_balance: 0,
+            account_balance: ntoy(1_000_000),
             account_locked_balance: 0,
             storage_usage: 0,
             attached_deposit: 0,
@@ -116,7 +346,7 @@ mod tests {
 
         let mut contract = Welcome::default();
         // this test did not call set_greeting so should return the default "Hello" greeting
-        contract.deposit("secret".to_string());
+        contract.deposit("secret".to_string(), 0);
 
         assert_eq!(
             ntoy(100),
@@ -132,18 +362,266 @@ mod tests {
         testing_env!(context.clone());
 
         let mut contract = Welcome::default();
-        // this test did not call set_greeting so should return the default "Hello" greeting
-        contract.deposit("secret".to_string());
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.deposit(digest.clone(), 0);
 
         assert_eq!(
             ntoy(100),
-            contract.get_deposit("secret".to_string())
+            contract.get_deposit(digest.clone())
         );
 
+        context.attached_deposit = 1;
+        testing_env!(context);
         contract.withdraw("secret".to_string());
         assert_eq!(
             ntoy(0),
-            contract.get_deposit("secret".to_string())
+            contract.get_deposit(digest)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Wrong key")]
+    fn test_withdraw_wrong_preimage_fails() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.attached_deposit = ntoy(100);
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.deposit(digest, 0);
+
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.withdraw("wrong-secret".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_withdraw_without_one_yocto_fails() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.attached_deposit = ntoy(100);
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.deposit(digest, 0);
+
+        context.attached_deposit = 0;
+        testing_env!(context);
+        contract.withdraw("secret".to_string());
+    }
+
+    #[test]
+    fn test_withdraw_correct_preimage_succeeds() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.attached_deposit = ntoy(100);
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.deposit(digest.clone(), 0);
+
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.withdraw("secret".to_string());
+        assert_eq!(
+            ntoy(0),
+            contract.get_deposit(digest)
+        );
+    }
+
+    #[test]
+    fn test_resolve_withdraw_success_leaves_balance_zeroed() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.attached_deposit = ntoy(100);
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.deposit(digest.clone(), 0);
+
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.withdraw("secret".to_string());
+
+        contract.apply_withdraw_result(true, digest.clone(), ntoy(100));
+        assert_eq!(ntoy(0), contract.get_deposit(digest));
+    }
+
+    #[test]
+    fn test_resolve_withdraw_failure_restores_balance() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.attached_deposit = ntoy(100);
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.deposit(digest.clone(), 0);
+
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.withdraw("secret".to_string());
+
+        contract.apply_withdraw_result(false, digest.clone(), ntoy(100));
+        assert_eq!(ntoy(100), contract.get_deposit(digest));
+    }
+
+    #[test]
+    #[should_panic(expected = "Deposit is still locked")]
+    fn test_reclaim_before_unlock_fails() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.attached_deposit = ntoy(100);
+        context.predecessor_account_id = "carol_near".to_string();
+        context.block_timestamp = 1_000;
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.deposit(digest.clone(), 2_000);
+
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.reclaim(digest);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_reclaim_without_one_yocto_fails() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.attached_deposit = ntoy(100);
+        context.predecessor_account_id = "carol_near".to_string();
+        context.block_timestamp = 2_000;
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.deposit(digest.clone(), 2_000);
+
+        context.attached_deposit = 0;
+        testing_env!(context);
+        contract.reclaim(digest);
+    }
+
+    #[test]
+    fn test_reclaim_after_unlock_succeeds() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.attached_deposit = ntoy(100);
+        context.predecessor_account_id = "carol_near".to_string();
+        context.block_timestamp = 1_000;
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.deposit(digest.clone(), 2_000);
+
+        context.attached_deposit = 1;
+        context.block_timestamp = 2_000;
+        testing_env!(context);
+        contract.reclaim(digest.clone());
+        assert_eq!(ntoy(0), contract.get_deposit(digest));
+    }
+
+    #[test]
+    fn test_resolve_reclaim_failure_restores_balance() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.attached_deposit = ntoy(100);
+        context.predecessor_account_id = "carol_near".to_string();
+        context.block_timestamp = 1_000;
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.deposit(digest.clone(), 2_000);
+
+        context.attached_deposit = 1;
+        context.block_timestamp = 2_000;
+        testing_env!(context);
+        contract.reclaim(digest.clone());
+
+        contract.apply_withdraw_result(false, digest.clone(), ntoy(100));
+        assert_eq!(ntoy(100), contract.get_deposit(digest));
+    }
+
+    #[test]
+    fn test_ft_on_transfer_populates_token_records() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.predecessor_account_id = "wrap_near".to_string();
+        testing_env!(context);
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        let unused = contract.ft_on_transfer(
+            "alice_near".to_string(),
+            U128(ntoy(100)),
+            digest.clone(),
         );
+
+        assert_eq!(U128(0), unused);
+        assert_eq!(ntoy(100), contract.get_token_deposit(digest));
+    }
+
+    #[test]
+    fn test_withdraw_ft_correct_preimage_succeeds() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.predecessor_account_id = "wrap_near".to_string();
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.ft_on_transfer("alice_near".to_string(), U128(ntoy(100)), digest.clone());
+
+        context.predecessor_account_id = "alice_near".to_string();
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.withdraw_ft("secret".to_string());
+        assert_eq!(ntoy(0), contract.get_token_deposit(digest));
+    }
+
+    #[test]
+    #[should_panic(expected = "Wrong key")]
+    fn test_withdraw_ft_wrong_preimage_fails() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.predecessor_account_id = "wrap_near".to_string();
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.ft_on_transfer("alice_near".to_string(), U128(ntoy(100)), digest);
+
+        context.predecessor_account_id = "alice_near".to_string();
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.withdraw_ft("wrong-secret".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Missing deposit")]
+    fn test_withdraw_ft_already_withdrawn_fails() {
+        let mut context = get_context(vec![], true);
+        context.is_view = false;
+        context.predecessor_account_id = "wrap_near".to_string();
+        testing_env!(context.clone());
+
+        let mut contract = Welcome::default();
+        let digest = hex::encode(env::sha256(b"secret"));
+        contract.ft_on_transfer("alice_near".to_string(), U128(ntoy(100)), digest);
+
+        context.predecessor_account_id = "alice_near".to_string();
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.withdraw_ft("secret".to_string());
+        contract.withdraw_ft("secret".to_string());
     }
 }